@@ -1,14 +1,15 @@
 use clap::{Parser, Subcommand};
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use color_eyre::Result;
 
 use tracing::{debug, Level};
 use tracing_subscriber::FmtSubscriber;
 
-use rust_remote_shell::device::Device;
-use rust_remote_shell::host::Host;
+use rust_remote_shell::device_server::DeviceServer;
+use rust_remote_shell::sender_client::{SenderClient, TrustSource};
 
 /// CLI for a rust remote shell
 #[derive(Debug, Parser)]
@@ -23,20 +24,54 @@ enum Commands {
     /// Host waiting for a device connection
     Host {
         addr: SocketAddr,
+        /// Serve with the given `--server-cert-file`/`--privkey-file` instead of the demo
+        /// keypair bundled under `certs/`. TLS is always on; this only selects which
+        /// keypair is served.
         #[clap(long, requires("server-cert-file"), requires("privkey-file"))]
-        tls_enabled: bool,
+        custom_tls_cert: bool,
         #[clap(long)]
         server_cert_file: Option<String>, // "certs/localhost.local.der"
         #[clap(long)]
         privkey_file: Option<String>, // "certs/localhost.local.key.der"
+        /// CA used to verify the device's client certificate. When set, only devices
+        /// presenting a certificate signed by this CA are accepted (mutual TLS).
+        #[clap(long)]
+        client_ca_file: Option<String>,
+        /// Let a peer that requests tunnel mode (a "tcp" or "udp" text frame as its first
+        /// message after authentication) pipe its connection to this address, which this host
+        /// connects to itself, e.g. `--forward 127.0.0.1:5432` for a database reachable from
+        /// this host. Every other connection stays a shell session.
+        #[clap(long)]
+        forward: Option<SocketAddr>,
+        /// Shared bearer token the device must send before any command is dispatched, a
+        /// second credential independent of the TLS keypair so access can be revoked
+        /// without reissuing certificates.
+        #[clap(long, env = "RUST_REMOTE_SHELL_AUTH_TOKEN")]
+        auth_token: Option<String>,
     },
     /// Device capable of receiving commands and sending their output
     Device {
+        /// URL of the host to connect to, e.g. `wss://127.0.0.1:8080`
         device_cfg_path: String,
         #[clap(long, requires("ca-cert-file"))]
         tls_enabled: bool,
         #[clap(long)]
         ca_cert_file: Option<String>, // "certs/CA.der"
+        /// Verify the host's certificate against the OS's native trust store instead of the
+        /// certificates bundled with this crate (Mozilla's root program via `webpki-roots`).
+        /// Ignored when `--tls-enabled` is set, since the `--ca-cert-file` it requires then
+        /// takes precedence.
+        #[clap(long)]
+        native_trust_store: bool,
+        /// Client certificate/key used to authenticate this device to the host (mutual TLS).
+        #[clap(long, requires("client-key-file"))]
+        client_cert_file: Option<String>,
+        #[clap(long, requires("client-cert-file"))]
+        client_key_file: Option<String>,
+        /// Shared bearer token sent to the host before any command is accepted, a second
+        /// credential independent of the TLS keypair.
+        #[clap(long, env = "RUST_REMOTE_SHELL_AUTH_TOKEN")]
+        auth_token: Option<String>,
     },
 }
 
@@ -58,53 +93,86 @@ async fn main() -> Result<()> {
     match cli.command {
         Commands::Host {
             addr,
-            tls_enabled,
+            custom_tls_cert,
             server_cert_file,
             privkey_file,
+            client_ca_file,
+            forward,
+            auth_token,
         } => {
-            let builder = Host::bind(addr).await?;
+            let mut server = DeviceServer::new(addr);
+
+            // when not set, `DeviceServer` falls back to the certificate/key shipped under
+            // `certs/` in this crate. TLS is always on either way.
+            if custom_tls_cert {
+                server = server.with_cert_files(
+                    PathBuf::from(
+                        server_cert_file
+                            .expect("expected to be called with --custom-tls-cert option"),
+                    ),
+                    PathBuf::from(
+                        privkey_file.expect("expected to be called with --custom-tls-cert option"),
+                    ),
+                );
+            }
 
-            if tls_enabled {
-                println!("TLS");
-
-                // retrieve certificates from the file names given in input and pass them as argument to with_tls()
-                let cert = tokio::fs::read(
-                    server_cert_file.expect("expected to be called with --tls-enabled option"),
-                )
-                .await
-                .expect("error while reading server certificate");
-
-                let privkey = tokio::fs::read(
-                    privkey_file.expect("expected to be called with --tls-enabled option"),
-                )
-                .await
-                .expect("error while reading server private key");
-
-                builder.with_tls(cert, privkey).await?.serve().await?;
-            } else {
-                builder.serve().await?;
+            // when set, only devices presenting a certificate signed by this CA are
+            // accepted (mutual TLS)
+            if let Some(client_ca_file) = client_ca_file {
+                server = server.with_client_auth(PathBuf::from(client_ca_file));
             }
+
+            if let Some(auth_token) = auth_token {
+                server = server.with_auth_token(auth_token);
+            }
+
+            // peers that request tunnel mode get their connection piped to `target`, which
+            // this host connects to; every other connection stays a shell session
+            if let Some(target) = forward {
+                server = server.with_forward(target);
+            }
+
+            server.listen().await?;
         }
         Commands::Device {
             device_cfg_path,
             tls_enabled,
             ca_cert_file,
+            native_trust_store,
+            client_cert_file,
+            client_key_file,
+            auth_token,
         } => {
             // To make comminicate a device with Astarte use the following command
             // astartectl appengine --appengine-url http://localhost:4002/ --realm-management-url http://localhost:4000/ --realm-key test_private.pem --realm-name test devices send-data 2TBn-jNESuuHamE2Zo1anA org.astarte-platform.rust-remote-shell.ConnectToHost /host '{"scheme" : "ws", "ip" : "127.0.0.1", "port" : 8080}'
-            let mut device = Device::new(device_cfg_path.as_str()).await?;
+            let listener_url = url::Url::parse(&device_cfg_path)?;
+
+            let mut client = SenderClient::new(listener_url);
 
             if tls_enabled {
-                let ca_cert = tokio::fs::read(
+                client = client.with_ca_file(PathBuf::from(
                     ca_cert_file.expect("expected to be called with --tls-enabled option"),
-                )
-                .await
-                .expect("error while reading server certificate");
+                ));
+            }
 
-                device.connect_tls(ca_cert).await?;
-            } else {
-                device.connect().await?;
+            if native_trust_store {
+                client = client.with_trust_source(TrustSource::Native);
             }
+
+            if let (Some(client_cert_file), Some(client_key_file)) =
+                (client_cert_file, client_key_file)
+            {
+                client = client.with_client_cert_files(
+                    PathBuf::from(client_cert_file),
+                    PathBuf::from(client_key_file),
+                );
+            }
+
+            if let Some(auth_token) = auth_token {
+                client = client.with_auth_token(auth_token);
+            }
+
+            client.connect().await?;
         }
     }
 
@@ -1,20 +1,28 @@
 use std::io::{self};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 use std::sync::Arc;
+use std::time::Duration;
 
-use futures::{SinkExt, StreamExt, TryStreamExt};
+use futures::{SinkExt, StreamExt};
 use thiserror::Error;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
-use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_rustls::server::TlsStream;
 use tokio_tungstenite::tungstenite::error::ProtocolError;
 use tokio_tungstenite::tungstenite::Error as TungsteniteError;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
 use tracing::{debug, error, info, instrument, warn};
 
 use crate::shell::{CommandHandler, ShellError};
+use crate::tunnel::{self, TunnelError};
+
+/// How long to wait for our TLS `close_notify` to be flushed and the write side of the
+/// connection shut down, before giving up and dropping the connection anyway.
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Error, Debug)]
 pub enum DeviceServerError {
@@ -34,21 +42,86 @@ pub enum DeviceServerError {
     ShellError(#[from] ShellError),
     #[error("Close websocket connection")]
     CloseWebsocket,
-    #[error("Error while establishing a TLS connection")]
-    RustTls(#[from] tokio_rustls::rustls::Error),
+    #[error("Error while configuring the TLS acceptor")]
+    Tls(#[from] crate::tls::TlsConfigError),
+    #[error("TLS handshake failed, e.g. the peer could not be authenticated")]
+    AcceptTls(#[source] io::Error),
+    #[error("Error while shutting down the TLS connection")]
+    ShutdownTls(#[source] io::Error),
+    #[error("Error while forwarding a tunneled connection")]
+    Tunnel(#[from] TunnelError),
+    #[error("Client failed to authenticate with the shared token")]
+    Unauthorized,
+    #[error("Client requested tunnel mode but no --forward target is configured")]
+    ForwardNotConfigured,
 }
 
 type TxErrorType = tokio::sync::mpsc::Sender<DeviceServerError>;
 const MAX_ERRORS_TO_HANDLE: usize = 10;
 
+const DEFAULT_CERT_FILE: &str = "certs/localhost.local.der";
+const DEFAULT_PRIVKEY_FILE: &str = "certs/localhost.local.key.der";
+
 #[derive(Debug)]
 pub struct DeviceServer {
     addr: SocketAddr,
+    cert_file: PathBuf,
+    privkey_file: PathBuf,
+    /// CA file used to verify an incoming client certificate. When set, the connecting peer
+    /// must present a certificate signed by one of these roots (mutual TLS).
+    client_ca_file: Option<PathBuf>,
+    /// Shared bearer token the connecting peer must send as its first message before any
+    /// command is dispatched. A second credential independent of the TLS keypair, so access
+    /// can be revoked without reissuing certificates.
+    auth_token: Option<String>,
+    /// The single address a peer is allowed to open a port-forwarding tunnel to (see
+    /// `crate::tunnel`). Only ever set by the host operator, never by the wire: a peer's
+    /// tunnel request picks the protocol, not the destination, so this can't be turned into
+    /// an open relay to an arbitrary address.
+    forward: Option<SocketAddr>,
 }
 
 impl DeviceServer {
     pub fn new(addr: SocketAddr) -> Self {
-        Self { addr }
+        Self {
+            addr,
+            cert_file: PathBuf::from(DEFAULT_CERT_FILE),
+            privkey_file: PathBuf::from(DEFAULT_PRIVKEY_FILE),
+            client_ca_file: None,
+            auth_token: None,
+            forward: None,
+        }
+    }
+
+    /// Loads the server certificate/private key from the given files instead of the default
+    /// `certs/` location.
+    pub fn with_cert_files(mut self, cert_file: PathBuf, privkey_file: PathBuf) -> Self {
+        self.cert_file = cert_file;
+        self.privkey_file = privkey_file;
+        self
+    }
+
+    /// Requires the connecting peer to authenticate with a certificate signed by a CA in
+    /// `client_ca_file` (mutual TLS).
+    pub fn with_client_auth(mut self, client_ca_file: PathBuf) -> Self {
+        self.client_ca_file = Some(client_ca_file);
+        self
+    }
+
+    /// Requires the connecting peer to send `auth_token` as its first message before any
+    /// command is dispatched.
+    pub fn with_auth_token(mut self, auth_token: String) -> Self {
+        self.auth_token = Some(auth_token);
+        self
+    }
+
+    /// Instead of a shell session, forward every connection accepted here to `target`
+    /// whenever a peer requests tunnel mode. Without this, any tunnel request is rejected:
+    /// the peer only ever picks a protocol (see `crate::tunnel::parse_request`), never the
+    /// destination address.
+    pub fn with_forward(mut self, target: SocketAddr) -> Self {
+        self.forward = Some(target);
+        self
     }
 
     #[instrument(skip(self))]
@@ -64,9 +137,24 @@ impl DeviceServer {
         let handles = Arc::new(Mutex::new(Vec::new()));
         let handles_clone = Arc::clone(&handles);
 
-        // create a TLS connection
-        let tls_config = Arc::new(server_tls_config().await?);
-        let acceptor = TlsAcceptor::from(tls_config);
+        // Build an acceptor that can have its certificate rotated at runtime (e.g. after an
+        // ACME renewal) without dropping connections already in progress, and reload it on
+        // SIGHUP for as long as this host keeps running.
+        let (acceptor, reload_handle) = crate::tls::reloadable_acceptor(
+            self.cert_file.clone(),
+            self.privkey_file.clone(),
+            self.client_ca_file.clone(),
+        )
+        .await?;
+
+        #[cfg(unix)]
+        crate::tls::spawn_sighup_reload(
+            reload_handle,
+            self.cert_file.clone(),
+            self.privkey_file.clone(),
+        );
+        #[cfg(not(unix))]
+        drop(reload_handle);
 
         let listener = TcpListener::bind(self.addr)
             .await
@@ -74,16 +162,28 @@ impl DeviceServer {
 
         info!("Listening at {}", self.addr);
 
+        let auth_token = self.auth_token.clone();
+        let forward = self.forward;
+
         // accept a new connection
         let handle_connections = tokio::spawn(async move {
             let acceptor_clone = acceptor.clone();
             while let Ok((stream, _)) = listener.accept().await {
-                let stream = acceptor_clone
-                    .accept(stream)
-                    .await
-                    .expect("expected TLS stream");
-                let handle_single_connection =
-                    tokio::spawn(Self::handle_connection(stream, tx_err.clone()));
+                let stream = match acceptor_clone.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        // e.g. the peer failed mutual TLS authentication: reject it and
+                        // keep listening instead of tearing down the whole server.
+                        warn!("TLS handshake failed: {}", err);
+                        continue;
+                    }
+                };
+                let handle_single_connection = tokio::spawn(Self::handle_connection(
+                    stream,
+                    tx_err.clone(),
+                    auth_token.clone(),
+                    forward,
+                ));
 
                 handles_clone.lock().await.push(handle_single_connection);
             }
@@ -121,15 +221,21 @@ impl DeviceServer {
     }
 
     #[instrument(skip_all)]
-    async fn handle_connection(stream: TlsStream<TcpStream>, tx_err: TxErrorType) {
-        match Self::impl_handle_connection(stream).await {
+    async fn handle_connection(
+        stream: TlsStream<TcpStream>,
+        tx_err: TxErrorType,
+        auth_token: Option<String>,
+        forward: Option<SocketAddr>,
+    ) {
+        match Self::impl_handle_connection(stream, auth_token, forward).await {
             Ok(_) => {}
             Err(DeviceServerError::CloseWebsocket)
+            | Err(DeviceServerError::Unauthorized)
+            | Err(DeviceServerError::ForwardNotConfigured)
             | Err(DeviceServerError::Transport(TungsteniteError::Protocol(
                 ProtocolError::ResetWithoutClosingHandshake,
             ))) => {
                 warn!("Websocket connection closed");
-                // TODO: check that the connection is effectively closed on the server-side (not only on the client-side)
             }
             Err(err) => {
                 error!("Fatal error occurred: {}", err);
@@ -139,7 +245,11 @@ impl DeviceServer {
     }
 
     #[instrument(skip_all)]
-    async fn impl_handle_connection(stream: TlsStream<TcpStream>) -> Result<(), DeviceServerError> {
+    async fn impl_handle_connection(
+        stream: TlsStream<TcpStream>,
+        auth_token: Option<String>,
+        forward: Option<SocketAddr>,
+    ) -> Result<(), DeviceServerError> {
         let addr = stream
             .get_ref()
             .0
@@ -147,73 +257,151 @@ impl DeviceServer {
             .map_err(|_| DeviceServerError::PeerAddr)?;
 
         //create a WebSocket connection
-        let web_socket_stream = accept_async(stream).await.map_err(|err| {
+        let mut ws_stream = accept_async(stream).await.map_err(|err| {
             error!("Websocket error: {:?}", err);
             DeviceServerError::WebSocketHandshake
         })?;
 
         info!("New WebSocket connection created over TLS: {}", addr);
 
-        // separate ownership between receiving and writing part
-        let (write, read) = web_socket_stream.split();
-
-        // Read the received command
-        read.map_err(DeviceServerError::Transport)
-            .and_then(|msg| async move {
-                info!("Received command from the client");
-                match msg {
-                    // convert the message from a Vec<u8> into a OsString
-                    Message::Binary(v) => {
-                        String::from_utf8(v).map_err(DeviceServerError::Utf8Error)
+        // A second credential independent of the TLS keypair: require the client's first
+        // message to be a binary frame carrying the shared bearer token, so access can be
+        // revoked without reissuing certificates. Compared in constant time to avoid leaking
+        // the token through the check's timing.
+        if let Some(expected_token) = &auth_token {
+            let authenticated = matches!(
+                ws_stream.next().await,
+                Some(Ok(Message::Binary(ref token))) if constant_time_eq(token, expected_token.as_bytes())
+            );
+
+            if !authenticated {
+                warn!("Client {} failed to authenticate, closing connection", addr);
+                let _ = ws_stream.send(Message::Close(None)).await;
+                return Err(DeviceServerError::Unauthorized);
+            }
+        }
+
+        // The first frame picks the operating mode for this connection: a `Message::Text`
+        // control frame of the form "tcp" / "udp" switches into port-forwarding tunnel mode
+        // (forwarding to the address the host was configured with via `--forward`, never one
+        // read off the wire); anything else keeps the historical shell behavior, with that
+        // first message fed into the normal command loop below so nothing is lost.
+        let first_msg = ws_stream.next().await;
+
+        if let Some(Ok(Message::Text(text))) = &first_msg {
+            if let Some(protocol) = tunnel::parse_request(text) {
+                let target = match forward {
+                    Some(target) => target,
+                    None => {
+                        warn!(
+                            "Client {} requested tunnel mode but no --forward target is configured, closing",
+                            addr
+                        );
+                        let _ = ws_stream.send(Message::Close(None)).await;
+                        return Err(DeviceServerError::ForwardNotConfigured);
                     }
-                    Message::Close(_) => Err(DeviceServerError::CloseWebsocket), // the client closed the connection
-                    _ => Err(DeviceServerError::ReadCommand),
-                }
-            })
-            .and_then(|cmd| async move {
-                // define a command handler
-                let cmd_handler = CommandHandler::default();
-
-                // execute the command and eventually return the error
-                let cmd_out = cmd_handler.execute(cmd).await.unwrap_or_else(|err| {
-                    warn!("Shell error: {}", err);
-                    format!("Shell error: {}\n", err)
-                });
-
-                info!("Send command output to the client");
-                Ok(Message::Binary(cmd_out.as_bytes().to_vec()))
-            })
-            .forward(write.sink_map_err(DeviceServerError::Transport))
-            .await?;
+                };
 
-        Ok(())
-    }
-}
+                info!("Switching connection {} into tunnel mode ({:?})", addr, protocol);
+                return tunnel::run(ws_stream, protocol, target)
+                    .await
+                    .map_err(DeviceServerError::Tunnel);
+            }
+        }
 
-#[instrument]
-async fn server_tls_config() -> Result<tokio_rustls::rustls::ServerConfig, DeviceServerError> {
-    let mut certs = Vec::new();
+        // Read the received commands, one message at a time, so that a close frame can be
+        // echoed back and the underlying TLS connection shut down cleanly instead of being
+        // dropped mid-handshake.
+        let mut pending = first_msg;
+        let closed_by_peer = loop {
+            let msg = match pending.take() {
+                Some(msg) => msg.map_err(DeviceServerError::Transport)?,
+                None => match ws_stream.next().await {
+                    Some(msg) => msg.map_err(DeviceServerError::Transport)?,
+                    None => break false,
+                },
+            };
+
+            match msg {
+                // convert the message from a Vec<u8> into a OsString
+                Message::Binary(v) => {
+                    info!("Received command from the client");
+                    let cmd = String::from_utf8(v).map_err(DeviceServerError::Utf8Error)?;
+
+                    // define a command handler
+                    let cmd_handler = CommandHandler::default();
+
+                    // execute the command and eventually return the error
+                    let cmd_out = cmd_handler.execute(cmd).await.unwrap_or_else(|err| {
+                        warn!("Shell error: {}", err);
+                        format!("Shell error: {}\n", err)
+                    });
+
+                    info!("Send command output to the client");
+                    ws_stream
+                        .send(Message::Binary(cmd_out.as_bytes().to_vec()))
+                        .await
+                        .map_err(DeviceServerError::Transport)?;
+                }
+                Message::Close(frame) => {
+                    // the client closed the connection: echo the close frame so the
+                    // websocket close handshake completes on both sides
+                    info!("Client requested close, echoing close frame");
+                    ws_stream
+                        .send(Message::Close(frame))
+                        .await
+                        .map_err(DeviceServerError::Transport)?;
+                    break true;
+                }
+                _ => return Err(DeviceServerError::ReadCommand),
+            }
+        };
 
-    let cert_file = tokio::fs::read("certs/localhost.local.der")
-        .await
-        .expect("no server cert found");
-    certs.push(tokio_rustls::rustls::Certificate(cert_file));
+        Self::shutdown_tls(ws_stream).await?;
 
-    debug!("certs created");
+        if closed_by_peer {
+            return Err(DeviceServerError::CloseWebsocket);
+        }
 
-    let privkey = tokio::fs::read("certs/localhost.local.key.der")
-        .await
-        .expect("no server private key found");
-    let privkey = tokio_rustls::rustls::PrivateKey(privkey);
-    debug!("private key retrieved");
+        Ok(())
+    }
 
-    let config = tokio_rustls::rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, privkey)
-        .map_err(DeviceServerError::RustTls)?;
+    /// Send `close_notify` and shut down the write side of the underlying TLS stream,
+    /// bounded by [`CLOSE_HANDSHAKE_TIMEOUT`] so a peer that stops reading can't wedge the
+    /// connection open forever. The peer's own `close_notify` is not waited for.
+    async fn shutdown_tls(
+        ws_stream: WebSocketStream<TlsStream<TcpStream>>,
+    ) -> Result<(), DeviceServerError> {
+        let mut stream = ws_stream.into_inner();
+
+        match tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, stream.shutdown()).await {
+            Ok(Ok(())) => Ok(()),
+            // the connection is already gone or can't take the close_notify: there's
+            // nothing left to shut down, so this isn't a fatal error
+            Ok(Err(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                debug!("TLS connection already closed, close_notify not sent: {err}");
+                Ok(())
+            }
+            Ok(Err(err)) => Err(DeviceServerError::ShutdownTls(err)),
+            Err(_) => {
+                warn!("TLS shutdown didn't complete within the timeout, dropping the connection");
+                Ok(())
+            }
+        }
+    }
+}
 
-    debug!("config created: {:?}", config);
+/// Compare two byte strings without branching on their contents, so a mismatching auth
+/// token can't be recovered byte-by-byte from the check's timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
 
-    Ok(config)
-}
\ No newline at end of file
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
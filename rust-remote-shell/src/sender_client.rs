@@ -1,7 +1,9 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::stream::SplitSink;
-use futures::{StreamExt, TryStreamExt};
+use futures::{SinkExt, StreamExt, TryStreamExt};
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::error::SendError;
@@ -9,29 +11,83 @@ use tokio::sync::mpsc::{Sender, UnboundedReceiver};
 use tokio::task::JoinHandle;
 use tokio::{io::AsyncWriteExt, sync::Mutex};
 use tokio_rustls::rustls;
-use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore};
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{
     connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
 };
-use tracing::{debug, error, info, instrument, trace};
+use tracing::{debug, error, info, instrument, trace, warn};
 use url::Url;
 
 use crate::io_handler::IOHandler;
+pub use crate::tls::TrustSource;
+
+/// How long to wait for the read/write tasks to wind down gracefully (including the TLS
+/// close handshake) before aborting them outright.
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 // configuration options for TLS connection
-async fn tls_client_config() -> ClientConfig {
+async fn tls_client_config(
+    ca_cert_file: Option<PathBuf>,
+    trust_source: TrustSource,
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+) -> Result<ClientConfig, SenderClientError> {
     let mut root_certs = RootCertStore::empty();
-    let cert_file = tokio::fs::read("certs/CA.der")
-        .await
-        .expect("no cert found");
-    let cert = Certificate(cert_file);
-    root_certs.add(&cert).unwrap();
 
-    ClientConfig::builder()
+    match ca_cert_file {
+        Some(ca_cert_file) => {
+            // accepts either a PEM bundle or a single raw DER certificate
+            let ca_bytes = tokio::fs::read(ca_cert_file)
+                .await
+                .map_err(SenderClientError::ReadFile)?;
+            for ca_cert in crate::tls::parse_cert_chain(&ca_bytes) {
+                root_certs
+                    .add(&ca_cert)
+                    .map_err(SenderClientError::RustTls)?;
+            }
+        }
+        None => match trust_source {
+            TrustSource::Native => crate::tls::add_native_trust_anchors(&mut root_certs)
+                .map_err(SenderClientError::ReadFile)?,
+            TrustSource::Webpki => {
+                root_certs.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+                    |ta| {
+                        OwnedTrustAnchor::from_subject_spki_name_constraints(
+                            ta.subject,
+                            ta.spki,
+                            ta.name_constraints,
+                        )
+                    },
+                ));
+            }
+        },
+    }
+
+    let builder = ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(root_certs)
-        .with_no_client_auth()
+        .with_root_certificates(root_certs);
+
+    let config = match (client_cert_file, client_key_file) {
+        (Some(client_cert_file), Some(client_key_file)) => {
+            let cert_bytes = tokio::fs::read(client_cert_file)
+                .await
+                .map_err(SenderClientError::ReadFile)?;
+            let key_bytes = tokio::fs::read(client_key_file)
+                .await
+                .map_err(SenderClientError::ReadFile)?;
+
+            builder
+                .with_single_cert(
+                    crate::tls::parse_cert_chain(&cert_bytes),
+                    crate::tls::parse_private_key(&key_bytes),
+                )
+                .map_err(SenderClientError::RustTls)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(config)
 }
 
 #[derive(Error, Debug)]
@@ -54,27 +110,82 @@ pub enum SenderClientError {
     },
     #[error("Server disconnected")]
     Disconnected,
+    #[error("Error while reading a certificate/key file")]
+    ReadFile(#[source] std::io::Error),
+    #[error("Error while establishing a TLS connection")]
+    RustTls(#[from] rustls::Error),
 }
 
 #[derive(Debug)]
 pub struct SenderClient {
     listener_url: Url,
-    tls_config: Arc<rustls::ClientConfig>,
+    /// CA file used to verify the host's certificate. When unset, falls back to
+    /// `trust_source`.
+    ca_cert_file: Option<PathBuf>,
+    /// Where to source the root certificates used to verify the host's certificate, when
+    /// `ca_cert_file` isn't set.
+    trust_source: TrustSource,
+    /// Client certificate/key used to authenticate this client to the host (mutual TLS).
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+    /// Shared bearer token sent as the first message once the connection is established, a
+    /// second credential independent of the TLS keypair.
+    auth_token: Option<String>,
 }
 
 impl SenderClient {
-    pub async fn new(listener_url: Url) -> Self {
-        let tls_config = Arc::new(tls_client_config().await);
+    pub fn new(listener_url: Url) -> Self {
         Self {
             listener_url,
-            tls_config,
+            ca_cert_file: None,
+            trust_source: TrustSource::default(),
+            client_cert_file: None,
+            client_key_file: None,
+            auth_token: None,
         }
     }
 
+    /// Verifies the host's certificate against `ca_cert_file` instead of `trust_source`.
+    pub fn with_ca_file(mut self, ca_cert_file: PathBuf) -> Self {
+        self.ca_cert_file = Some(ca_cert_file);
+        self
+    }
+
+    /// Picks where the root certificates used to verify the host's certificate come from,
+    /// when no `ca_cert_file` is set. Defaults to [`TrustSource::Webpki`].
+    pub fn with_trust_source(mut self, trust_source: TrustSource) -> Self {
+        self.trust_source = trust_source;
+        self
+    }
+
+    /// Authenticates this client to the host with the given client certificate and private
+    /// key (mutual TLS).
+    pub fn with_client_cert_files(mut self, client_cert_file: PathBuf, client_key_file: PathBuf) -> Self {
+        self.client_cert_file = Some(client_cert_file);
+        self.client_key_file = Some(client_key_file);
+        self
+    }
+
+    /// Sends `auth_token` as the first frame once connected.
+    pub fn with_auth_token(mut self, auth_token: String) -> Self {
+        self.auth_token = Some(auth_token);
+        self
+    }
+
     #[instrument(skip(self))]
     pub async fn connect(&mut self) -> Result<(), SenderClientError> {
+        let tls_config = Arc::new(
+            tls_client_config(
+                self.ca_cert_file.clone(),
+                self.trust_source.clone(),
+                self.client_cert_file.clone(),
+                self.client_key_file.clone(),
+            )
+            .await?,
+        );
+
         // Websocket connection to an existing server
-        let connector = Connector::Rustls(Arc::clone(&self.tls_config));
+        let connector = Connector::Rustls(tls_config);
         let (ws_stream, _) =
             connect_async_tls_with_config(self.listener_url.clone(), None, Some(connector))
                 .await
@@ -87,6 +198,15 @@ impl SenderClient {
 
         info!("WebSocket handshake has been successfully completed on a TLS protected stream");
 
+        // authenticate with the shared bearer token before anything else goes over the wire,
+        // symmetric to the check the device performs on the first frame it receives
+        if let Some(token) = &self.auth_token {
+            ws_stream
+                .send(Message::Binary(token.clone().into_bytes()))
+                .await
+                .map_err(SenderClientError::WebSocketConnect)?;
+        }
+
         let (write, read) = ws_stream.split();
 
         let (tx_cmd_out, rx_cmd_out) = tokio::sync::mpsc::unbounded_channel::<Message>();
@@ -103,6 +223,13 @@ impl SenderClient {
             let res = read
                 .map_err(|err| SenderClientError::TungsteniteReadData { err })
                 .try_for_each(|cmd_out| async {
+                    // the server closed the connection: stop forwarding frames and wind
+                    // down through the same path as any other disconnect, instead of
+                    // passing the raw close frame along to be printed as if it were
+                    // command output
+                    if matches!(cmd_out, Message::Close(_)) {
+                        return Err(SenderClientError::Disconnected);
+                    }
                     tx_cmd_out.send(cmd_out).map_err(SenderClientError::Channel)
                 })
                 .await;
@@ -149,9 +276,24 @@ impl SenderClient {
         handles: &mut [JoinHandle<Result<(), SenderClientError>>],
         rx_cmd_out: Arc<Mutex<UnboundedReceiver<Message>>>,
     ) -> Result<(), SenderClientError> {
-        // abort the current active tasks
-        for h in handles.iter() {
-            h.abort();
+        // Give both tasks a single bounded window to wind down on their own after the
+        // server's close frame (or a transport error) stopped `handle_read`: `read_write`
+        // still has its last few stdin/stdout round trips to flush. A peer that never lets
+        // either task finish must not be able to wedge this routine forever, so whatever
+        // hasn't finished by the deadline is aborted instead of waited on.
+        //
+        // This is best-effort, not a full mirror of `DeviceServer::shutdown_tls`: the
+        // write half of the TLS stream is owned by `IOHandler` (`io_handler.rs`) for the
+        // lifetime of `read_write`, so there's no close frame/`shutdown()` call to make
+        // from here without that type handing the sink back first.
+        if tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, futures::future::join_all(handles.iter_mut()))
+            .await
+            .is_err()
+        {
+            warn!("Tasks didn't finish within the close handshake timeout, aborting them");
+            for h in handles.iter() {
+                h.abort();
+            }
         }
 
         for h in handles {
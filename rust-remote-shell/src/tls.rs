@@ -1,13 +1,17 @@
+use arc_swap::ArcSwap;
 use futures::Future;
 
-use rustls_pemfile::{read_all, read_one, Item};
+use rustls_pemfile::{read_all, Item};
 use std::io::BufReader;
 
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio_rustls::rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
 use tokio_rustls::rustls::{
     Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore,
 };
@@ -23,93 +27,330 @@ use url::Url;
 use crate::device::DeviceError;
 use crate::host::{HostBuilder, HostError};
 
+/// Where to source the root certificates used to verify the host's TLS certificate.
+///
+/// How this combines with an explicit CA file depends on the caller: [`client_tls_config`]
+/// adds this source on top of the CA file's certificates, while
+/// [`crate::sender_client::SenderClient`] uses this source only when no CA file is set. A
+/// missing/unreadable CA file is returned as an error instead of panicking; it is not
+/// replaced by this source.
+#[derive(Debug, Clone, Default)]
+pub enum TrustSource {
+    /// Trust the certificates bundled with this crate (Mozilla's root program via
+    /// `webpki-roots`). This is the historical default.
+    #[default]
+    Webpki,
+    /// Trust the certificates configured in the OS's native certificate store.
+    Native,
+}
+
+/// Populate `root_certs` with the OS native trust anchors, skipping any certificate the
+/// platform returns that cannot be parsed as a valid trust anchor.
+pub(crate) fn add_native_trust_anchors(root_certs: &mut RootCertStore) -> std::io::Result<()> {
+    let native_certs = rustls_native_certs::load_native_certs()?;
+
+    for cert in native_certs {
+        match webpki::TrustAnchor::try_from_cert_der(&cert.0) {
+            Ok(_) => root_certs
+                .add(&Certificate(cert.0))
+                .unwrap_or_else(|err| debug!("skipping unparsable native trust anchor: {:?}", err)),
+            Err(err) => debug!("skipping invalid native certificate: {:?}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a certificate chain out of `bytes`, accepting either a PEM bundle (one or more
+/// `-----BEGIN CERTIFICATE-----` blocks, e.g. a leaf followed by intermediates) or a single
+/// raw DER certificate. PEM is tried first; if no PEM items are found the bytes are assumed
+/// to already be DER, so both ACME/openssl `.pem` output and the crate's historical `.der`
+/// files work unmodified.
+pub(crate) fn parse_cert_chain(bytes: &[u8]) -> Vec<Certificate> {
+    let mut reader = BufReader::new(bytes);
+    let pem_certs: Vec<Certificate> = read_all(&mut reader)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(cert) => Some(Certificate(cert)),
+            _ => None,
+        })
+        .collect();
+
+    if pem_certs.is_empty() {
+        vec![Certificate(bytes.to_vec())]
+    } else {
+        pem_certs
+    }
+}
+
+/// Parse a private key out of `bytes`, accepting PEM-encoded PKCS#8, RSA or EC keys, or a
+/// single raw DER key. Falls back to treating `bytes` as DER when no PEM key item is found.
+pub(crate) fn parse_private_key(bytes: &[u8]) -> PrivateKey {
+    let mut reader = BufReader::new(bytes);
+    let pem_key = read_all(&mut reader).unwrap_or_default().into_iter().find_map(|item| {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        }
+    });
+
+    PrivateKey(pem_key.unwrap_or_else(|| bytes.to_vec()))
+}
+
+/// Errors building a server-side TLS configuration from certificate/key files, shared by
+/// [`acceptor`] and [`reloadable_acceptor`] (and, through them, by whichever listener wires
+/// them in, e.g. [`crate::device_server::DeviceServer`]).
+#[derive(Error, Debug)]
+pub enum TlsConfigError {
+    #[error("Error while reading {}", file.display())]
+    ReadFile {
+        #[source]
+        err: std::io::Error,
+        file: PathBuf,
+    },
+    #[error("Error while configuring TLS")]
+    RustTls(#[from] tokio_rustls::rustls::Error),
+}
+
 #[instrument(skip_all)]
-pub async fn server_tls_config<C, P>(
-    cert: C,
-    privkey: P,
-) -> Result<tokio_rustls::rustls::ServerConfig, HostError>
-where
-    C: Into<Vec<u8>>,
-    P: Into<Vec<u8>>,
-{
-    let certs = vec![Certificate(cert.into())];
+pub async fn server_tls_config(
+    certs: Vec<Certificate>,
+    privkey: PrivateKey,
+    client_ca: Option<RootCertStore>,
+) -> Result<tokio_rustls::rustls::ServerConfig, TlsConfigError> {
+    let builder = tokio_rustls::rustls::ServerConfig::builder().with_safe_defaults();
 
-    let config = tokio_rustls::rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, PrivateKey(privkey.into()))
-        .map_err(HostError::RustTls)?;
+    let config = match client_ca {
+        Some(client_ca) => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_ca))
+            .with_single_cert(certs, privkey)
+            .map_err(TlsConfigError::RustTls)?,
+        None => builder
+            .with_no_client_auth()
+            .with_single_cert(certs, privkey)
+            .map_err(TlsConfigError::RustTls)?,
+    };
 
     debug!("config created: {:?}", config);
 
     Ok(config)
 }
 
+fn load_client_ca(client_ca_file: &Path) -> Result<RootCertStore, TlsConfigError> {
+    let ca_bytes = std::fs::read(client_ca_file).map_err(|err| TlsConfigError::ReadFile {
+        err,
+        file: client_ca_file.to_path_buf(),
+    })?;
+
+    let mut root_certs = RootCertStore::empty();
+    for ca_cert in parse_cert_chain(&ca_bytes) {
+        root_certs.add(&ca_cert).map_err(TlsConfigError::RustTls)?;
+    }
+    Ok(root_certs)
+}
+
 pub async fn acceptor(
     host_cert_file: PathBuf,
     privkey_file: PathBuf,
-) -> Result<TlsAcceptor, HostError> {
-    let cert_item = retrieve_item(&host_cert_file).map_err(|err| HostError::ReadFile {
+    client_ca_file: Option<PathBuf>,
+) -> Result<TlsAcceptor, TlsConfigError> {
+    let cert_bytes = std::fs::read(&host_cert_file).map_err(|err| TlsConfigError::ReadFile {
         err,
         file: host_cert_file,
     })?;
-    let cert = match cert_item {
-        Some(Item::X509Certificate(ca_cert)) => ca_cert,
-        _ => return Err(HostError::WrongItem),
-    };
+    let certs = parse_cert_chain(&cert_bytes);
 
-    let privkey_item = retrieve_item(&privkey_file).map_err(|err| HostError::ReadFile {
+    let privkey_bytes = std::fs::read(&privkey_file).map_err(|err| TlsConfigError::ReadFile {
         err,
         file: privkey_file,
     })?;
-    let privkey = match privkey_item {
-        Some(Item::PKCS8Key(privkey)) => privkey,
-        _ => return Err(HostError::WrongItem),
-    };
+    let privkey = parse_private_key(&privkey_bytes);
 
-    let acceptor = TlsAcceptor::from(Arc::new(server_tls_config(cert, privkey).await?));
+    let client_ca = client_ca_file.as_deref().map(load_client_ca).transpose()?;
+
+    let acceptor = TlsAcceptor::from(Arc::new(server_tls_config(certs, privkey, client_ca).await?));
     Ok(acceptor)
 }
 
-fn retrieve_item(file: &Path) -> Result<Option<Item>, std::io::Error> {
-    std::fs::File::open(file)
-        .map(BufReader::new)
-        .and_then(|mut reader| read_one(&mut reader))
+/// A [`ResolvesServerCert`] backed by an [`ArcSwap`], so the certificate a `ServerConfig`
+/// hands out to new connections can be rotated (e.g. after an ACME renewal) without
+/// rebuilding the `TlsAcceptor` or dropping sessions already in progress: they keep using
+/// the `Arc<CertifiedKey>` they resolved at handshake time.
+struct ReloadableCertResolver {
+    current: ArcSwap<CertifiedKey>,
+}
+
+impl ReloadableCertResolver {
+    fn new(cert: CertifiedKey) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(cert),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
 }
 
-pub async fn client_tls_config(ca_cert_file: Option<PathBuf>) -> Result<Connector, DeviceError> {
+/// A handle to reload the certificate served by a [`TlsAcceptor`] built with
+/// [`reloadable_acceptor`], without affecting connections already in progress.
+#[derive(Clone)]
+pub struct CertReloadHandle {
+    resolver: Arc<ReloadableCertResolver>,
+}
+
+impl CertReloadHandle {
+    /// Re-read the certificate/private key files and atomically swap the certificate new
+    /// connections will be served. In-flight connections keep using the previous one.
+    #[instrument(skip(self))]
+    pub async fn reload(
+        &self,
+        host_cert_file: PathBuf,
+        privkey_file: PathBuf,
+    ) -> Result<(), TlsConfigError> {
+        let cert = load_certified_key(host_cert_file, privkey_file).await?;
+        self.resolver.current.store(Arc::new(cert));
+        debug!("TLS certificate reloaded");
+        Ok(())
+    }
+}
+
+async fn load_certified_key(
+    host_cert_file: PathBuf,
+    privkey_file: PathBuf,
+) -> Result<CertifiedKey, TlsConfigError> {
+    let cert_bytes = std::fs::read(&host_cert_file).map_err(|err| TlsConfigError::ReadFile {
+        err,
+        file: host_cert_file,
+    })?;
+    let certs = parse_cert_chain(&cert_bytes);
+
+    let privkey_bytes = std::fs::read(&privkey_file).map_err(|err| TlsConfigError::ReadFile {
+        err,
+        file: privkey_file,
+    })?;
+    let privkey = parse_private_key(&privkey_bytes);
+
+    // `any_supported_type` returns a `Box<dyn SigningKey>`; `CertifiedKey::new` wants it
+    // wrapped in an `Arc` so the resolved key can be shared across connections/clones.
+    let signing_key = Arc::new(sign::any_supported_type(&privkey).map_err(TlsConfigError::RustTls)?);
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Like [`acceptor`], but the returned acceptor serves a certificate that can be rotated at
+/// runtime through the returned [`CertReloadHandle`], e.g. from a SIGHUP handler or a
+/// file-watcher, so a long-running host can pick up a renewed cert without a restart.
+pub async fn reloadable_acceptor(
+    host_cert_file: PathBuf,
+    privkey_file: PathBuf,
+    client_ca_file: Option<PathBuf>,
+) -> Result<(TlsAcceptor, CertReloadHandle), TlsConfigError> {
+    let cert = load_certified_key(host_cert_file, privkey_file).await?;
+    let resolver = Arc::new(ReloadableCertResolver::new(cert));
+
+    let client_ca = client_ca_file.as_deref().map(load_client_ca).transpose()?;
+
+    let builder = tokio_rustls::rustls::ServerConfig::builder().with_safe_defaults();
+    let config = match client_ca {
+        Some(client_ca) => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_ca))
+            .with_cert_resolver(resolver.clone()),
+        None => builder
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone()),
+    };
+
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    Ok((acceptor, CertReloadHandle { resolver }))
+}
+
+/// Reload `host_cert_file`/`privkey_file` into `handle` every time this process receives a
+/// `SIGHUP`, for as long as the returned task is kept running. This is the common trigger
+/// for picking up certificates renewed by an external ACME client.
+#[cfg(unix)]
+pub fn spawn_sighup_reload(
+    handle: CertReloadHandle,
+    host_cert_file: PathBuf,
+    privkey_file: PathBuf,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(sighup) => sighup,
+            Err(err) => {
+                tracing::error!("failed to install SIGHUP handler: {}", err);
+                return;
+            }
+        };
+
+        while sighup.recv().await.is_some() {
+            debug!("SIGHUP received, reloading TLS certificate");
+            if let Err(err) = handle
+                .reload(host_cert_file.clone(), privkey_file.clone())
+                .await
+            {
+                tracing::error!("failed to reload TLS certificate: {}", err);
+            }
+        }
+    })
+}
+
+pub async fn client_tls_config(
+    ca_cert_file: Option<PathBuf>,
+    trust_source: TrustSource,
+    client_cert_file: Option<PathBuf>,
+    client_key_file: Option<PathBuf>,
+) -> Result<Connector, DeviceError> {
     let mut root_certs = RootCertStore::empty();
 
     if let Some(ca_cert_file) = ca_cert_file {
-        let file = std::fs::File::open(ca_cert_file).map_err(DeviceError::ReadFile)?;
-        let mut reader = BufReader::new(file);
-
-        for item in read_all(&mut reader).map_err(DeviceError::ReadFile)? {
-            match item {
-                Item::X509Certificate(ca_cert) => {
-                    let cert = Certificate(ca_cert);
-                    debug!("{:?}", cert);
-                    root_certs
-                        .add(&cert)
-                        .expect("failed to add CA cert to the root certs");
-                }
-                _ => return Err(DeviceError::WrongItem),
-            }
+        let ca_bytes = std::fs::read(ca_cert_file).map_err(DeviceError::ReadFile)?;
+
+        for ca_cert in parse_cert_chain(&ca_bytes) {
+            debug!("{:?}", ca_cert);
+            root_certs.add(&ca_cert).map_err(DeviceError::RustTls)?;
         }
     };
 
-    root_certs.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-        OwnedTrustAnchor::from_subject_spki_name_constraints(
-            ta.subject,
-            ta.spki,
-            ta.name_constraints,
-        )
-    }));
+    match trust_source {
+        TrustSource::Native => {
+            add_native_trust_anchors(&mut root_certs).map_err(DeviceError::ReadFile)?
+        }
+        TrustSource::Webpki => {
+            root_certs.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+                |ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                },
+            ));
+        }
+    }
 
-    let config = ClientConfig::builder()
+    let builder = ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(root_certs)
-        .with_no_client_auth();
+        .with_root_certificates(root_certs);
+
+    let config = match (client_cert_file, client_key_file) {
+        (Some(client_cert_file), Some(client_key_file)) => {
+            let client_cert_bytes =
+                std::fs::read(client_cert_file).map_err(DeviceError::ReadFile)?;
+            let client_key_bytes = std::fs::read(client_key_file).map_err(DeviceError::ReadFile)?;
+
+            builder
+                .with_single_cert(
+                    parse_cert_chain(&client_cert_bytes),
+                    parse_private_key(&client_key_bytes),
+                )
+                .map_err(DeviceError::RustTls)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
 
     Ok(Connector::Rustls(Arc::new(config)))
 }
@@ -0,0 +1,174 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use futures::{SinkExt, StreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{info, instrument};
+
+/// The protocol of the local socket a tunnel connection is forwarded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelProtocol {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for TunnelProtocol {
+    type Err = TunnelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(TunnelError::UnknownProtocol(s.to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TunnelError {
+    #[error("Unknown tunnel protocol {0:?}, expected \"tcp\" or \"udp\"")]
+    UnknownProtocol(String),
+    #[error("Error while connecting to the forwarded address")]
+    Connect(#[source] std::io::Error),
+    #[error("Error while reading/writing the forwarded socket")]
+    Io(#[source] std::io::Error),
+    #[error("Transport error from Tungstenite")]
+    Transport(#[from] tokio_tungstenite::tungstenite::Error),
+}
+
+/// A single WebSocket binary frame is large enough to carry one `recv`/`read` worth of data;
+/// for UDP this also bounds the largest datagram that can be forwarded.
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Parse a `Message::Text` control frame of the form `"<tcp|udp>"` into the requested
+/// protocol, returning `None` if `text` isn't a tunnel request (so the caller can fall back
+/// to treating the connection as a regular shell session).
+///
+/// The peer only picks the protocol: the address forwarded to is always the one the host
+/// was configured with (see `DeviceServer::with_forward`), never a value read off the wire,
+/// so a connecting peer can't turn the host into an open relay to an arbitrary address.
+pub fn parse_request(text: &str) -> Option<TunnelProtocol> {
+    text.trim().parse().ok()
+}
+
+/// Turn `ws` into a byte pipe between the WebSocket channel and `target`: one direction
+/// copies `Message::Binary` frames into the forwarded TCP/UDP socket, the other frames socket
+/// reads back into `Message::Binary` frames. Both directions run concurrently as futures on
+/// the calling task; no task is spawned. For UDP each datagram is forwarded as exactly one
+/// WebSocket frame, so the frame boundary doubles as the length prefix needed to keep
+/// datagrams from running together.
+#[instrument(skip(ws))]
+pub async fn run<S>(
+    ws: WebSocketStream<S>,
+    protocol: TunnelProtocol,
+    target: SocketAddr,
+) -> Result<(), TunnelError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match protocol {
+        TunnelProtocol::Tcp => run_tcp(ws, target).await,
+        TunnelProtocol::Udp => run_udp(ws, target).await,
+    }
+}
+
+async fn run_tcp<S>(ws: WebSocketStream<S>, target: SocketAddr) -> Result<(), TunnelError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let socket = TcpStream::connect(target)
+        .await
+        .map_err(TunnelError::Connect)?;
+    info!("Forwarding TCP connection to {}", target);
+
+    let (mut socket_read, mut socket_write) = socket.into_split();
+    let (mut ws_write, mut ws_read) = ws.split();
+
+    let socket_to_ws = async {
+        let mut buf = vec![0u8; BUF_SIZE];
+        loop {
+            let n = socket_read.read(&mut buf).await.map_err(TunnelError::Io)?;
+            if n == 0 {
+                break;
+            }
+            ws_write
+                .send(Message::Binary(buf[..n].to_vec()))
+                .await
+                .map_err(TunnelError::Transport)?;
+        }
+        ws_write.close().await.map_err(TunnelError::Transport)
+    };
+
+    let ws_to_socket = async {
+        while let Some(msg) = ws_read.next().await {
+            match msg.map_err(TunnelError::Transport)? {
+                Message::Binary(data) => {
+                    socket_write
+                        .write_all(&data)
+                        .await
+                        .map_err(TunnelError::Io)?;
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+
+    tokio::try_join!(socket_to_ws, ws_to_socket)?;
+    Ok(())
+}
+
+async fn run_udp<S>(ws: WebSocketStream<S>, target: SocketAddr) -> Result<(), TunnelError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let local_addr: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    }
+    .parse()
+    .expect("valid wildcard address");
+
+    let socket = UdpSocket::bind(local_addr)
+        .await
+        .map_err(TunnelError::Connect)?;
+    socket.connect(target).await.map_err(TunnelError::Connect)?;
+    info!("Forwarding UDP datagrams to {}", target);
+
+    let (mut ws_write, mut ws_read) = ws.split();
+
+    let socket_to_ws = async {
+        let mut buf = vec![0u8; BUF_SIZE];
+        loop {
+            let n = socket.recv(&mut buf).await.map_err(TunnelError::Io)?;
+            ws_write
+                .send(Message::Binary(buf[..n].to_vec()))
+                .await
+                .map_err(TunnelError::Transport)?;
+        }
+    };
+
+    let ws_to_socket = async {
+        while let Some(msg) = ws_read.next().await {
+            match msg.map_err(TunnelError::Transport)? {
+                Message::Binary(data) => {
+                    socket.send(&data).await.map_err(TunnelError::Io)?;
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+
+    tokio::select! {
+        res = socket_to_ws => res,
+        res = ws_to_socket => res,
+    }
+}